@@ -5,6 +5,7 @@
 use std::env;
 use std::error::Error;
 use std::ffi::OsString;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::path::{Path, PathBuf};
@@ -14,71 +15,109 @@ use log;
 
 use crate::Result;
 
-/// Return a sequence of arguments derived from ripgrep rc configuration files.
+/// Return a sequence of arguments derived from ripgrep rc configuration
+/// files.
+///
+/// Every applicable `.ripgreprc` is parsed and their arguments are
+/// flattened together in outermost-to-innermost order, so that a more
+/// specific (deeper) config file can override a less specific one via
+/// ripgrep's normal last-flag-wins semantics. See `config_paths`.
 pub fn args() -> Vec<OsString> {
-    let config_path = match config_path() {
-        None => return vec![],
-        Some(config_path) => config_path,
-    };
+    args_with_provenance()
+        .into_iter()
+        .map(|(_, config_arg)| config_arg.value)
+        .collect()
+}
 
-    let (args, errs) = match parse(&config_path) {
-        Ok((args, errs)) => (args, errs),
-        Err(err) => {
-            message!(
-                "failed to read the file specified in RIPGREP_CONFIG_PATH: {}",
-                err
-            );
-            return vec![];
-        }
-    };
+/// Like `args`, but also returns the config file each argument came from
+/// and the span in that file it was parsed from.
+///
+/// `print_debug` uses this to print config provenance; most callers want
+/// the simpler `args` instead.
+fn args_with_provenance() -> Vec<(PathBuf, ConfigArg)> {
+    let mut args = vec![];
+    for config_path in config_paths() {
+        let (config_args, errs) = match parse(&config_path) {
+            Ok((config_args, errs)) => (config_args, errs),
+            Err(err) => {
+                message!(
+                    "failed to read the config file {}: {}",
+                    config_path.display(),
+                    err
+                );
+                continue;
+            }
+        };
 
-    if !errs.is_empty() {
-        for err in errs {
-            message!("{}:{}", config_path.display(), err);
+        if !errs.is_empty() {
+            for err in errs {
+                message!("{}:{}", config_path.display(), err);
+            }
         }
+        log::debug!(
+            "{}: arguments loaded from config file: {:?}",
+            config_path.display(),
+            config_args
+        );
+        args.extend(
+            config_args
+                .into_iter()
+                .map(|config_arg| (config_path.clone(), config_arg)),
+        );
     }
-    log::debug!(
-        "{}: arguments loaded from config file: {:?}",
-        config_path.display(),
-        args
-    );
     args
 }
 
-/// returns the path of a config file in this precedence
-/// 1) cwd
-/// 2) env specified
-/// 3) somewhere up the tree from cwd
-fn config_path() -> Option<PathBuf> {
-    let cwd_opt = cwd_ripgreprc();
-    if cwd_opt.is_some() {
-        return cwd_opt;
-    }
-
-    let env_opt = env_ripgreprc();
-    if env_opt.is_some()  {
-        return env_opt;
+/// Print every argument contributed by ripgrep's config files, one per
+/// line, alongside the file and `line:column` it was parsed from.
+///
+/// This shows which `.ripgreprc` is responsible for a given flag. No
+/// command line flag calls it; it runs only when called directly.
+pub fn print_debug() {
+    for (path, config_arg) in args_with_provenance() {
+        println!("{}", debug_line(&path, &config_arg));
     }
+}
 
-    return find_ripgreprc();
+/// Format a single provenance-annotated argument the way `print_debug`
+/// prints it. Split out from `print_debug` so the output format can be
+/// unit tested without going through stdout.
+fn debug_line(path: &Path, config_arg: &ConfigArg) -> String {
+    format!("{}:{}: {:?}", path.display(), config_arg.span, config_arg.value)
 }
 
-/// if there is a ripgreprc in the cwd, get it
-fn cwd_ripgreprc() -> Option<PathBuf> {
-    let mut cwd = env::current_dir().unwrap();
-    let file = Path::new(".ripgreprc");
+/// Returns every `.ripgreprc` that applies to the current directory, in the
+/// order their arguments should be applied.
+///
+/// This walks from the filesystem root down to the current directory,
+/// collecting every `.ripgreprc` found along the way, outermost first. That
+/// way, a `.ripgreprc` in a subdirectory is parsed after (and so can
+/// override) a `.ripgreprc` higher up the tree. If `RIPGREP_CONFIG_PATH` is
+/// set, it names one additional config file that is appended last, giving
+/// it the highest precedence of all.
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
 
-    cwd.push(file);
+    if let Ok(cwd) = env::current_dir() {
+        let mut ancestors: Vec<&Path> = cwd.ancestors().collect();
+        ancestors.reverse();
+        for dir in ancestors {
+            let candidate = dir.join(".ripgreprc");
+            if candidate.is_file() {
+                paths.push(candidate);
+            }
+        }
+    }
 
-    if cwd.is_file() {
-        return Some(cwd);
+    if let Some(env_path) = env_ripgreprc() {
+        paths.push(env_path);
     }
 
-    None
+    paths
 }
 
 /// if we have a ripgreprc specified in env, get it
-fn env_ripgreprc() -> Option<PathBuf> { 
+fn env_ripgreprc() -> Option<PathBuf> {
     match env::var_os("RIPGREP_CONFIG_PATH") {
         None => None,
         Some(config_path) => {
@@ -91,27 +130,27 @@ fn env_ripgreprc() -> Option<PathBuf> {
     }
 }
 
-/// Find a .ripgreprc file in the tree
-fn find_ripgreprc() -> Option<PathBuf> {
-    let mut search_path = env::current_dir().unwrap();
-    let file = Path::new(".ripgreprc");
+/// A position within a config file, given as a line number together with a
+/// 1-indexed, end-exclusive column range on that line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Span {
+    line: usize,
+    start_col: usize,
+    end_col: usize,
+}
 
-    // go up one, since we know it's not in the current folder already
-    if !search_path.pop() {
-        return None;
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.start_col)
     }
+}
 
-    loop {
-        search_path.push(file);
-
-        if search_path.is_file() {
-            break Some(search_path);
-        }
-
-        if !(search_path.pop() && search_path.pop()) {
-            break None;
-        }
-    }
+/// A single argument parsed out of a config file, together with the span it
+/// was parsed from.
+#[derive(Clone, Debug)]
+struct ConfigArg {
+    value: OsString,
+    span: Span,
 }
 
 /// Parse a single ripgrep rc file from the given path.
@@ -124,7 +163,7 @@ fn find_ripgreprc() -> Option<PathBuf> {
 /// for each line in addition to successfully parsed arguments.
 fn parse<P: AsRef<Path>>(
     path: P,
-) -> Result<(Vec<OsString>, Vec<Box<dyn Error>>)> {
+) -> Result<(Vec<ConfigArg>, Vec<Box<dyn Error>>)> {
     let path = path.as_ref();
     match File::open(&path) {
         Ok(file) => parse_reader(file),
@@ -138,41 +177,212 @@ fn parse<P: AsRef<Path>>(
 /// own buffer internally.
 ///
 /// On success, this returns a set of shell arguments, in order, that should
-/// be pre-pended to the arguments given to ripgrep at the command line.
+/// be pre-pended to the arguments given to ripgrep at the command line, each
+/// tagged with the line:column span it was parsed from. Each line is
+/// tokenized using shell-style quoting rules (see `tokenize_line`), so a
+/// single line may expand into zero, one or several arguments.
 ///
 /// If the reader could not be read, then an error is returned. If there was a
 /// problem parsing one or more lines, then errors are returned for each line
-/// in addition to successfully parsed arguments.
+/// in addition to successfully parsed arguments. Each error is prefixed with
+/// the `line:column` it occurred at.
 fn parse_reader<R: io::Read>(
     rdr: R,
-) -> Result<(Vec<OsString>, Vec<Box<dyn Error>>)> {
+) -> Result<(Vec<ConfigArg>, Vec<Box<dyn Error>>)> {
     let mut bufrdr = io::BufReader::new(rdr);
     let (mut args, mut errs) = (vec![], vec![]);
     let mut line_number = 0;
     bufrdr.for_byte_line_with_terminator(|line| {
         line_number += 1;
 
-        let line = line.trim();
-        if line.is_empty() || line[0] == b'#' {
+        // Strip only the line terminator itself, not all trailing
+        // whitespace: a line can end in a meaningful (e.g. escaped) space,
+        // and it's the tokenizer's job to decide what that means, not
+        // ours. Leading whitespace is likewise left for the tokenizer to
+        // skip, so that column positions in a `Span` line up with the
+        // original file.
+        let mut line = line;
+        while matches!(line.last(), Some(b'\n') | Some(b'\r')) {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() {
             return Ok(true);
         }
-        match line.to_os_str() {
-            Ok(osstr) => {
-                args.push(osstr.to_os_string());
-            }
-            Err(err) => {
-                errs.push(format!("{}: {}", line_number, err).into());
+        let (tokens, lex_err) = tokenize_line(line_number, line);
+        for (token, span) in tokens {
+            match token.to_os_str() {
+                Ok(osstr) => {
+                    args.push(ConfigArg { value: osstr.to_os_string(), span });
+                }
+                Err(err) => {
+                    errs.push(format!("{}: {}", span, err).into());
+                }
             }
         }
+        if let Some((err, span)) = lex_err {
+            errs.push(format!("{}: {}", span, err).into());
+        }
         Ok(true)
     })?;
     Ok((args, errs))
 }
 
+/// The state of the small state machine used by `tokenize_line` to split a
+/// config line into shell-style tokens.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum LexState {
+    /// Not inside any quoting. Whitespace terminates the current token and
+    /// `#` starts a trailing comment that runs to the end of the line.
+    Default,
+    /// Inside a `'...'` span. Every byte is literal; there are no escapes.
+    SingleQuote,
+    /// Inside a `"..."` span. `\` escapes `"` and `\`; every other byte is
+    /// literal.
+    DoubleQuote,
+}
+
+/// Split a single config line into zero or more shell-style tokens, each
+/// tagged with the span (1-indexed, end-exclusive columns on `line_number`)
+/// it was parsed from.
+///
+/// This honors POSIX-ish quoting: a `'` enters a single-quote span where
+/// everything up to the next `'` is literal, a `"` enters a double-quote
+/// span where `\` escapes only `"` and `\`, and a bare `\` outside of any
+/// quote escapes the following byte literally. A `#` encountered outside of
+/// a quote starts a comment that runs to the end of the line (this also
+/// covers a line that is a comment in its entirety).
+///
+/// Each call tokenizes a single physical line in isolation: a quote that is
+/// still open at the end of the line is always an error, there is no
+/// continuation onto the next line.
+///
+/// If the line ends while still inside a quote, the tokens parsed so far
+/// are returned along with an error describing the unterminated quote,
+/// spanning from the start of the token in which that quote opened to the
+/// end of the line.
+fn tokenize_line(
+    line_number: usize,
+    line: &[u8],
+) -> (Vec<(Vec<u8>, Span)>, Option<(String, Span)>) {
+    let mut tokens = vec![];
+    let mut cur = vec![];
+    let mut in_token = false;
+    let mut token_start = 0;
+    let mut state = LexState::Default;
+
+    // Where scanning actually stopped: either the end of the line, or the
+    // byte index of a `#` that started a trailing comment. Used below to
+    // size spans that end at end-of-line, so a comment glued directly onto
+    // a token (e.g. `--foo=bar#comment`) doesn't get credited to the span.
+    let mut end_idx = line.len();
+
+    let mut i = 0;
+    while i < line.len() {
+        let b = line[i];
+        match state {
+            LexState::Default => match b {
+                b' ' | b'\t' | b'\r' | b'\n' => {
+                    if in_token {
+                        tokens.push((
+                            std::mem::take(&mut cur),
+                            Span {
+                                line: line_number,
+                                start_col: token_start + 1,
+                                end_col: i + 1,
+                            },
+                        ));
+                        in_token = false;
+                    }
+                }
+                b'#' => {
+                    end_idx = i;
+                    break;
+                }
+                b'\'' => {
+                    if !in_token {
+                        token_start = i;
+                    }
+                    state = LexState::SingleQuote;
+                    in_token = true;
+                }
+                b'"' => {
+                    if !in_token {
+                        token_start = i;
+                    }
+                    state = LexState::DoubleQuote;
+                    in_token = true;
+                }
+                b'\\' => {
+                    if !in_token {
+                        token_start = i;
+                    }
+                    i += 1;
+                    if i < line.len() {
+                        cur.push(line[i]);
+                        in_token = true;
+                    }
+                }
+                _ => {
+                    if !in_token {
+                        token_start = i;
+                    }
+                    cur.push(b);
+                    in_token = true;
+                }
+            },
+            LexState::SingleQuote => {
+                if b == b'\'' {
+                    state = LexState::Default;
+                } else {
+                    cur.push(b);
+                }
+            }
+            LexState::DoubleQuote => match b {
+                b'"' => state = LexState::Default,
+                b'\\' if i + 1 < line.len()
+                    && matches!(line[i + 1], b'"' | b'\\') =>
+                {
+                    i += 1;
+                    cur.push(line[i]);
+                }
+                _ => cur.push(b),
+            },
+        }
+        i += 1;
+    }
+    let end_span = Span {
+        line: line_number,
+        start_col: token_start + 1,
+        end_col: end_idx + 1,
+    };
+    if in_token {
+        tokens.push((cur, end_span));
+    }
+    let err = match state {
+        LexState::Default => None,
+        LexState::SingleQuote => {
+            Some(("unterminated ' quote".to_string(), end_span))
+        }
+        LexState::DoubleQuote => {
+            Some(("unterminated \" quote".to_string(), end_span))
+        }
+    };
+    (tokens, err)
+}
+
 #[cfg(test)]
 mod tests {
     use super::parse_reader;
+    use std::env;
     use std::ffi::OsString;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn values(args: Vec<super::ConfigArg>) -> Vec<String> {
+        args.into_iter()
+            .map(|a| a.value.into_string().unwrap())
+            .collect()
+    }
 
     #[test]
     fn basic() {
@@ -190,9 +400,10 @@ mod tests {
         )
         .unwrap();
         assert!(errs.is_empty());
-        let args: Vec<String> =
-            args.into_iter().map(|s| s.into_string().unwrap()).collect();
-        assert_eq!(args, vec!["--context=0", "--smart-case", "-u", "--foo",]);
+        assert_eq!(
+            values(args),
+            vec!["--context=0", "--smart-case", "-u", "--foo",]
+        );
     }
 
     // We test that we can handle invalid UTF-8 on Unix-like systems.
@@ -210,6 +421,8 @@ baz
         )
         .unwrap();
         assert!(errs.is_empty());
+        let args: Vec<OsString> =
+            args.into_iter().map(|a| a.value).collect();
         assert_eq!(
             args,
             vec![
@@ -233,6 +446,213 @@ baz
         )
         .unwrap();
         assert_eq!(errs.len(), 1);
+        let args: Vec<OsString> =
+            args.into_iter().map(|a| a.value).collect();
         assert_eq!(args, vec![OsString::from("quux"), OsString::from("baz"),]);
     }
+
+    #[test]
+    fn quoting() {
+        let (args, errs) = parse_reader(
+            &b"\
+--glob '!*.min.js'
+-g \"*.rs\"
+--foo=\"bar baz\"
+trailing # a comment
+# whole line comment
+escaped\\ space
+"[..],
+        )
+        .unwrap();
+        assert!(errs.is_empty());
+        assert_eq!(
+            values(args),
+            vec![
+                "--glob",
+                "!*.min.js",
+                "-g",
+                "*.rs",
+                "--foo=bar baz",
+                "trailing",
+                "escaped space",
+            ]
+        );
+    }
+
+    // A trailing backslash-escaped space right before the line terminator
+    // must survive: the line-terminator strip in `parse_reader` must not
+    // eat it before the tokenizer sees it.
+    #[test]
+    fn escaped_trailing_space_is_preserved() {
+        let (args, errs) = parse_reader(&b"foo\\ \n"[..]).unwrap();
+        assert!(errs.is_empty());
+        assert_eq!(values(args), vec!["foo "]);
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        let (args, errs) = parse_reader(
+            &b"\
+--foo
+--bar='unterminated
+--baz
+"[..],
+        )
+        .unwrap();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(
+            values(args),
+            vec!["--foo", "--bar=unterminated", "--baz"]
+        );
+    }
+
+    // A double-quoted value does not continue onto the next physical line:
+    // each line is tokenized on its own, so an opening quote left unclosed
+    // at end of line is always an unterminated-quote error on *that* line
+    // (the trailing `\` just before the line break has no next byte to
+    // escape on its own line, so it is taken literally), and a quote that
+    // opens on the next line is just another, independent unterminated
+    // quote rather than a continuation of the first.
+    #[test]
+    fn double_quote_does_not_span_lines() {
+        let (args, errs) = parse_reader(
+            &b"\
+--foo=\"bar\\
+baz\"
+"[..],
+        )
+        .unwrap();
+        assert_eq!(errs.len(), 2);
+        assert_eq!(values(args), vec!["--foo=bar\\", "baz"]);
+    }
+
+    #[test]
+    fn spans_have_line_and_column() {
+        let (args, errs) = parse_reader(
+            &b"\
+--foo
+  --bar 'baz qux'
+"[..],
+        )
+        .unwrap();
+        assert!(errs.is_empty());
+        assert_eq!(args[0].value, OsString::from("--foo"));
+        assert_eq!(args[0].span.line, 1);
+        assert_eq!(args[0].span.start_col, 1);
+
+        assert_eq!(args[1].value, OsString::from("--bar"));
+        assert_eq!(args[1].span.line, 2);
+        assert_eq!(args[1].span.start_col, 3);
+
+        assert_eq!(args[2].value, OsString::from("baz qux"));
+        assert_eq!(args[2].span.line, 2);
+        assert_eq!(args[2].span.start_col, 9);
+    }
+
+    // A `#` glued directly onto a token with no separating whitespace still
+    // starts a comment, and the token's span should end where the token
+    // actually ends, not stretch across the stripped comment text.
+    #[test]
+    fn comment_with_no_leading_whitespace_does_not_widen_span() {
+        let (args, errs) = parse_reader(&b"--foo=bar#comment\n"[..]).unwrap();
+        assert!(errs.is_empty());
+        assert_eq!(args.len(), 1);
+        assert_eq!(args[0].value, OsString::from("--foo=bar"));
+        assert_eq!(args[0].span.start_col, 1);
+        assert_eq!(args[0].span.end_col, 10);
+    }
+
+    #[test]
+    fn error_message_includes_line_and_column() {
+        let (_, errs) = parse_reader(
+            &b"\
+--foo
+  --bar='unterminated
+"[..],
+        )
+        .unwrap();
+        assert_eq!(errs.len(), 1);
+        assert_eq!(errs[0].to_string(), "2:3: unterminated ' quote");
+    }
+
+    #[test]
+    fn debug_line_includes_file_and_span() {
+        let (args, errs) = parse_reader(&b"--foo\n"[..]).unwrap();
+        assert!(errs.is_empty());
+        let line = super::debug_line(Path::new("/tmp/.ripgreprc"), &args[0]);
+        assert_eq!(line, "/tmp/.ripgreprc:1:1: \"--foo\"");
+    }
+
+    // `config_paths` reads the process-wide current directory and the
+    // RIPGREP_CONFIG_PATH environment variable, both of which are shared
+    // by the whole test binary. Serialize every test that touches either so
+    // they can't stomp on one another when the test suite runs in parallel.
+    static CWD_MUTEX: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    // Runs `test` with the current directory set to `dir` and
+    // `RIPGREP_CONFIG_PATH` cleared, restoring both afterward. Holds
+    // `CWD_MUTEX` for the duration.
+    fn with_cwd<T>(dir: &Path, test: impl FnOnce() -> T) -> T {
+        let _guard = CWD_MUTEX.lock().unwrap();
+        let original_cwd = env::current_dir().unwrap();
+        let original_env = env::var_os("RIPGREP_CONFIG_PATH");
+        env::remove_var("RIPGREP_CONFIG_PATH");
+        env::set_current_dir(dir).unwrap();
+
+        let result = test();
+
+        env::set_current_dir(original_cwd).unwrap();
+        match original_env {
+            Some(val) => env::set_var("RIPGREP_CONFIG_PATH", val),
+            None => env::remove_var("RIPGREP_CONFIG_PATH"),
+        }
+        result
+    }
+
+    #[test]
+    fn config_paths_orders_outermost_to_innermost() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        let child = root.join("child");
+        fs::create_dir(&child).unwrap();
+        fs::write(root.join(".ripgreprc"), "--one").unwrap();
+        fs::write(child.join(".ripgreprc"), "--two").unwrap();
+
+        let paths: Vec<PathBuf> = with_cwd(&child, super::config_paths)
+            .into_iter()
+            .filter(|p| p.starts_with(&root))
+            .collect();
+        assert_eq!(paths, vec![root.join(".ripgreprc"), child.join(".ripgreprc")]);
+    }
+
+    #[test]
+    fn config_paths_empty_when_none_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+
+        let paths: Vec<PathBuf> = with_cwd(&dir, super::config_paths)
+            .into_iter()
+            .filter(|p| p.starts_with(&dir))
+            .collect();
+        assert!(paths.is_empty());
+    }
+
+    #[test]
+    fn config_paths_applies_env_var_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+        let ancestor = dir.join("child");
+        fs::create_dir(&ancestor).unwrap();
+        fs::write(dir.join(".ripgreprc"), "--one").unwrap();
+        let env_path = dir.join("env.ripgreprc");
+        fs::write(&env_path, "--two").unwrap();
+
+        let paths = with_cwd(&ancestor, || {
+            env::set_var("RIPGREP_CONFIG_PATH", &env_path);
+            super::config_paths()
+        });
+        let paths: Vec<PathBuf> =
+            paths.into_iter().filter(|p| p.starts_with(&dir)).collect();
+        assert_eq!(paths, vec![dir.join(".ripgreprc"), env_path]);
+    }
 }